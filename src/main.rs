@@ -1,5 +1,8 @@
+use clap::Args;
 use clap::Parser;
+use clap::Subcommand;
 use color_eyre::eyre::bail;
+use color_eyre::eyre::eyre;
 use color_eyre::Result;
 use directories::BaseDirs;
 use directories::ProjectDirs;
@@ -7,17 +10,24 @@ use globset::{Glob, GlobSetBuilder};
 use ignore::DirEntry;
 use ignore::WalkBuilder;
 use jiff::{tz::TimeZone, Timestamp, ToSpan};
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
 use serde::Deserialize;
 use serde::Serialize;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::fs;
+use std::io::Write;
 use std::net::IpAddr;
+use std::path::Path;
 use std::path::PathBuf;
 use std::process::Stdio;
 use std::str::FromStr;
+use std::time::Duration;
+use std::time::Instant;
+use tempfile::NamedTempFile;
 use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::process::Command;
+use tokio::sync::mpsc;
 use tokio::sync::oneshot;
 use tracing::debug;
 use tracing::info;
@@ -33,10 +43,31 @@ const THIS_CRATE_NAME: &'static str = env!("CARGO_PKG_NAME");
 const DEFAULT_LOG_LEVEL: &'static str = "INFO";
 const DEFAULT_TIMEZONE: &'static str = "UTC";
 const DEFAULT_TIMESTAMP_FMT: &'static str = "%Y-%m-%d_%T";
+const DEFAULT_DEBOUNCE_MS: u64 = 2000;
 
 #[derive(Debug, Parser)]
 #[command(version, about, long_about=None)]
 struct Cli {
+    #[command(subcommand)]
+    command: Commands,
+
+    /// Log level (TRACE, DEBUG, INFO, WARN, ERROR).
+    #[clap(long, short, global = true)]
+    log_level: Option<tracing::Level>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Commands {
+    /// Sync configured sources to the remote.
+    Run(RunArgs),
+
+    /// Validate config.toml and the local/remote environment without syncing anything,
+    /// reporting every problem found instead of stopping at the first one.
+    Check,
+}
+
+#[derive(Debug, Args)]
+struct RunArgs {
     /// Do not execute the rsync command, only print what would be executed.
     #[clap(short, long, group = "dry-run")]
     dry_run: bool,
@@ -46,9 +77,18 @@ struct Cli {
     #[clap(long, group = "dry-run")]
     rsync_dry_run: bool,
 
-    /// Log level (TRACE, DEBUG, INFO, WARN, ERROR).
-    #[clap(long, short)]
-    log_level: Option<tracing::Level>,
+    /// After the initial sync, keep running and re-sync whenever files under
+    /// the configured sources change.
+    #[clap(short, long)]
+    watch: bool,
+
+    /// Force backing up to the named remote instead of probing for the fastest one.
+    #[clap(long)]
+    remote: Option<String>,
+
+    /// Skip the latency probe and use the first configured remote.
+    #[clap(long)]
+    no_probe: bool,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -97,6 +137,10 @@ fn default_relative_to() -> VarPath {
     VarPath::from_str("${HOME}").unwrap()
 }
 
+fn default_debounce_ms() -> u64 {
+    DEFAULT_DEBOUNCE_MS
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 struct GeneralSettings {
     log_level: Option<String>,
@@ -114,6 +158,15 @@ struct GeneralSettings {
     #[serde(default = "default_timestamp_fmt")]
     timestamp_fmt: String,
 
+    /// How long to wait for a burst of filesystem events to go quiet, in
+    /// milliseconds, before re-syncing in `--watch` mode.
+    #[serde(default = "default_debounce_ms")]
+    debounce_ms: u64,
+
+    /// Snapshot retention policy. Only applies when `remote.destination` includes
+    /// `${timestamp}`, in which case each run creates its own snapshot directory.
+    retention: Option<RetentionSettings>,
+
     #[serde(flatten)]
     ignore_settings: IgnoreSettings,
 }
@@ -124,12 +177,23 @@ impl Default for GeneralSettings {
             exclude: Vec::new(),
             timezone: default_timezone(),
             timestamp_fmt: default_timestamp_fmt(),
+            debounce_ms: default_debounce_ms(),
+            retention: None,
             ignore_settings: IgnoreSettings::default(),
             relative_to: default_relative_to(),
         }
     }
 }
 
+#[derive(Debug, Deserialize, Serialize, Clone)]
+struct RetentionSettings {
+    /// Keep at most this many most-recent snapshots.
+    keep_last: Option<usize>,
+
+    /// Additionally keep every snapshot newer than this many days.
+    keep_newer_than_days: Option<i64>,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 struct SyncSettings {
     path: PathBuf,
@@ -140,55 +204,736 @@ struct SyncSettings {
 
 #[derive(Debug, Deserialize, Serialize)]
 struct RemoteSettings {
+    /// Name used to refer to this remote from `--remote`.
+    name: String,
     user: String,
     host: IpAddr,
     destination: VarPath,
+    /// Optional SSH transport overrides (port, identity file, host key policy, ...).
+    ssh: Option<SshSettings>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+struct SshSettings {
+    /// SSH port to connect on. Defaults to rsync/ssh's own default (22) if unset.
+    port: Option<u16>,
+
+    /// Private key file to authenticate with, e.g. `${HOME}/.ssh/id_ed25519`.
+    identity_file: Option<VarPath>,
+
+    /// Custom `known_hosts` file, passed as `ssh -o UserKnownHostsFile=...`.
+    known_hosts: Option<VarPath>,
+
+    /// `ssh -o StrictHostKeyChecking=...` policy, e.g. "accept-new" or "no".
+    strict_host_key_checking: Option<String>,
+
+    /// Arbitrary extra `-o Key=Value` options passed through to ssh verbatim.
+    #[serde(default)]
+    options: Vec<String>,
+}
+
+/// Translate a `SshSettings` block into the command string rsync's `-e` expects.
+/// `identity_file` and `known_hosts` are run through the same `VarPath::eval` pipeline
+/// as `RemoteSettings.destination`, so e.g. `${HOME}` expands.
+fn build_ssh_command(ssh: &SshSettings, variables: &Environment) -> Result<String> {
+    let parts: Vec<String> = ssh_flag_args(ssh, variables)?
+        .iter()
+        .map(|part| shell_quote(part))
+        .collect();
+    Ok(format!("ssh {}", parts.join(" ")))
+}
+
+/// The `-p`/`-i`/`-o` flag/value pairs a `SshSettings` block expands to, shared between
+/// rsync's `-e` command string and direct `ssh` invocations (used for snapshot listing
+/// and pruning).
+fn ssh_flag_args(ssh: &SshSettings, variables: &Environment) -> Result<Vec<String>> {
+    let mut args = Vec::new();
+
+    if let Some(port) = ssh.port {
+        args.push(String::from("-p"));
+        args.push(port.to_string());
+    }
+
+    if let Some(identity_file) = &ssh.identity_file {
+        let identity_path = identity_file.eval(variables)?;
+        args.push(String::from("-i"));
+        args.push(identity_path.display().to_string());
+    }
+
+    if let Some(known_hosts) = &ssh.known_hosts {
+        let known_hosts_path = known_hosts.eval(variables)?;
+        args.push(String::from("-o"));
+        args.push(format!(
+            "UserKnownHostsFile={}",
+            known_hosts_path.display()
+        ));
+    }
+
+    if let Some(policy) = &ssh.strict_host_key_checking {
+        args.push(String::from("-o"));
+        args.push(format!("StrictHostKeyChecking={}", policy));
+    }
+
+    for option in &ssh.options {
+        args.push(String::from("-o"));
+        args.push(option.clone());
+    }
+
+    Ok(args)
+}
+
+/// POSIX single-quote a value for safe interpolation into a remote shell command.
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+/// Build a direct (non-rsync) `ssh user@host <remote_cmd>` invocation, reusing
+/// `build_ssh_command`'s transport settings.
+fn remote_ssh_command(
+    remote: &RemoteSettings,
+    variables: &Environment,
+    remote_cmd: &str,
+) -> Result<Command> {
+    let mut cmd = Command::new("ssh");
+    if let Some(ssh) = &remote.ssh {
+        cmd.args(ssh_flag_args(ssh, variables)?);
+    }
+    cmd.arg(format!("{}@{}", remote.user, remote.host));
+    cmd.arg(remote_cmd);
+    Ok(cmd)
+}
+
+/// Whether `remote.destination` is a per-run snapshot directory, i.e. its template
+/// includes `${timestamp}`.
+fn is_snapshot_destination(remote: &RemoteSettings) -> bool {
+    remote.destination.to_string().contains("${timestamp}")
+}
+
+/// List the timestamped snapshot directories directly under `parent_dir` on `remote`,
+/// oldest first, by parsing each entry's name against `timestamp_fmt`.
+async fn list_remote_snapshots(
+    remote: &RemoteSettings,
+    variables: &Environment,
+    parent_dir: &Path,
+    timestamp_fmt: &str,
+) -> Result<Vec<(Timestamp, String)>> {
+    let list_cmd = format!("ls -1 {}", shell_quote(&parent_dir.display().to_string()));
+    let mut cmd = remote_ssh_command(remote, variables, &list_cmd)?;
+    let output = cmd.output().await?;
+    if !output.status.success() {
+        bail!(
+            "Unable to list snapshots under {}: {}",
+            parent_dir.display(),
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    let mut snapshots = Vec::new();
+    for name in String::from_utf8_lossy(&output.stdout).lines() {
+        if let Ok(ts) = Timestamp::strptime(timestamp_fmt, name) {
+            snapshots.push((ts, name.to_string()));
+        }
+    }
+    snapshots.sort_by_key(|(ts, _)| *ts);
+    Ok(snapshots)
+}
+
+/// Given snapshots oldest-first, return the names `retention` says should be deleted.
+/// An empty/under-specified `RetentionSettings` (both fields `None`) keeps everything.
+fn snapshots_to_prune(
+    snapshots: &[(Timestamp, String)],
+    retention: &RetentionSettings,
+) -> Result<Vec<String>> {
+    if retention.keep_last.is_none() && retention.keep_newer_than_days.is_none() {
+        return Ok(Vec::new());
+    }
+
+    let mut keep: HashSet<&str> = HashSet::new();
+
+    if let Some(keep_last) = retention.keep_last {
+        for (_, name) in snapshots.iter().rev().take(keep_last) {
+            keep.insert(name);
+        }
+    }
+
+    if let Some(days) = retention.keep_newer_than_days {
+        // Timestamp arithmetic rejects spans with day units, so count days as 24 hours.
+        let cutoff = Timestamp::now().checked_sub((days * 24).hours())?;
+        for (ts, name) in snapshots {
+            if *ts >= cutoff {
+                keep.insert(name);
+            }
+        }
+    }
+
+    Ok(snapshots
+        .iter()
+        .filter(|(_, name)| !keep.contains(name.as_str()))
+        .map(|(_, name)| name.clone())
+        .collect())
+}
+
+/// Remove whichever snapshots under `parent_dir` fall outside `retention`'s policy.
+/// Honors `--dry-run` by only printing what would be removed.
+async fn prune_snapshots(
+    remote: &RemoteSettings,
+    variables: &Environment,
+    parent_dir: &Path,
+    timestamp_fmt: &str,
+    retention: &RetentionSettings,
+    dry_run: bool,
+) -> Result<()> {
+    let snapshots = list_remote_snapshots(remote, variables, parent_dir, timestamp_fmt).await?;
+    let prune = snapshots_to_prune(&snapshots, retention)?;
+
+    if prune.is_empty() {
+        debug!("No snapshots to prune under {}", parent_dir.display());
+        return Ok(());
+    }
+
+    for name in &prune {
+        let target = parent_dir.join(name);
+        if dry_run {
+            println!("[dry-run] would remove snapshot {}", target.display());
+            continue;
+        }
+        info!("Removing snapshot {}", target.display());
+        let remove_cmd = format!("rm -rf {}", shell_quote(&target.display().to_string()));
+        let mut cmd = remote_ssh_command(remote, variables, &remove_cmd)?;
+        let status = cmd.status().await?;
+        if !status.success() {
+            bail!("Failed to remove snapshot {}", target.display());
+        }
+    }
+
+    Ok(())
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 struct Config {
     general: Option<GeneralSettings>,
     sync: Vec<SyncSettings>,
-    remote: RemoteSettings,
+    /// Candidate backup destinations. When more than one is configured, the fastest
+    /// reachable candidate is selected automatically unless `--remote` or `--no-probe`
+    /// is given.
+    remotes: Vec<RemoteSettings>,
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    color_eyre::install()?;
-    let default_log_level = tracing::Level::DEBUG;
+/// Probe a single remote's reachability and round-trip latency with a lightweight,
+/// read-only `rsync --dry-run --list-only` call, returning the elapsed time on success.
+/// Uses the remote's own SSH transport settings, same as the real sync.
+///
+/// `rsync --list-only` fails on a destination whose parent directory hasn't been
+/// created yet, which is the normal state before a remote's first run, so a failure
+/// here falls back to a bare `ssh ... true` connectivity check before giving up.
+async fn probe_remote(
+    rsync: &Path,
+    remote: &RemoteSettings,
+    variables: &Environment,
+    remote_arg: &str,
+    ssh_command: Option<&str>,
+) -> Option<Duration> {
+    let mut cmd = Command::new(rsync);
+    cmd.args(["--dry-run", "--list-only", remote_arg]);
+    if let Some(ssh_command) = ssh_command {
+        cmd.args(["-e", ssh_command]);
+    }
+    cmd.stdout(Stdio::null());
+    cmd.stderr(Stdio::null());
 
-    let Some(proj_dirs) = ProjectDirs::from("", "", THIS_CRATE_NAME) else {
-        bail!("Unable to get XDG dirs");
+    let start = Instant::now();
+    match cmd.status().await {
+        Ok(status) if status.success() => return Some(start.elapsed()),
+        Ok(status) => debug!(
+            "Probe of {} exited with {}; falling back to a connectivity check",
+            remote_arg, status
+        ),
+        Err(err) => debug!("Probe of {} failed to start: {}", remote_arg, err),
+    }
+
+    let mut cmd = match remote_ssh_command(remote, variables, "true") {
+        Ok(cmd) => cmd,
+        Err(err) => {
+            debug!("Unable to build connectivity check for '{}': {}", remote.name, err);
+            return None;
+        }
     };
-    let config_dir = proj_dirs.config_dir();
-    let config_file = config_dir.join("config.toml");
-    let contents = fs::read_to_string(&config_file)?;
-    let config: Config = toml::from_str(&contents)?;
-    let general_settings = match config.general {
-        Some(ref general) => general.clone(),
-        None => GeneralSettings::default(),
+    cmd.stdout(Stdio::null());
+    cmd.stderr(Stdio::null());
+
+    let start = Instant::now();
+    match cmd.status().await {
+        Ok(status) if status.success() => Some(start.elapsed()),
+        Ok(status) => {
+            debug!("Connectivity check of '{}' exited with {}", remote.name, status);
+            None
+        }
+        Err(err) => {
+            debug!("Connectivity check of '{}' failed to start: {}", remote.name, err);
+            None
+        }
+    }
+}
+
+/// Pick which configured remote to back up to.
+///
+/// If `forced` names a remote, that one is used unconditionally. Otherwise, unless
+/// `no_probe` is set (in which case the first configured remote is used), every
+/// candidate is probed and the fastest reachable one wins.
+async fn select_remote<'a>(
+    rsync: &Path,
+    remotes: &'a [RemoteSettings],
+    variables: &Environment,
+    forced: Option<&str>,
+    no_probe: bool,
+) -> Result<&'a RemoteSettings> {
+    if remotes.is_empty() {
+        bail!("No remotes configured");
+    }
+
+    if let Some(name) = forced {
+        return remotes
+            .iter()
+            .find(|remote| remote.name == name)
+            .ok_or_else(|| eyre!("No remote named '{}' configured", name));
+    }
+
+    if no_probe {
+        debug!(
+            "Skipping remote probe; using first configured remote '{}'",
+            remotes[0].name
+        );
+        return Ok(&remotes[0]);
+    }
+
+    let mut fastest: Option<(&RemoteSettings, Duration)> = None;
+    for remote in remotes {
+        // Probe the destination's parent, not the destination itself: when
+        // `${timestamp}` is in play (chunk0-5 snapshots) the leaf directory is brand
+        // new every run and doesn't exist yet, which would make `--list-only` fail.
+        let destination_dir = remote.destination.eval(variables)?;
+        let probe_dir = destination_dir.parent().unwrap_or(Path::new("/"));
+        let remote_arg = format!(
+            "{user}@{host}:{dest}",
+            user = remote.user,
+            host = remote.host,
+            dest = probe_dir.display()
+        );
+        let ssh_command = match &remote.ssh {
+            Some(ssh) => Some(build_ssh_command(ssh, variables)?),
+            None => None,
+        };
+        match probe_remote(rsync, remote, variables, &remote_arg, ssh_command.as_deref()).await {
+            Some(elapsed) => {
+                info!("Probed remote '{}' in {:?}", remote.name, elapsed);
+                if fastest.as_ref().map_or(true, |(_, best)| elapsed < *best) {
+                    fastest = Some((remote, elapsed));
+                }
+            }
+            None => warn!("Remote '{}' is unreachable, skipping", remote.name),
+        }
+    }
+
+    let Some((remote, elapsed)) = fastest else {
+        bail!("No configured remote was reachable");
     };
-    let remote_settings = &config.remote;
+    info!("Selected remote '{}' ({:?})", remote.name, elapsed);
+    Ok(remote)
+}
 
-    let args = Cli::parse();
-    let dry_run = args.dry_run;
-    let rsync_dry_run = args.rsync_dry_run;
-    let log_level = match args.log_level {
-        Some(level) => level,
-        None => match config.general {
-            Some(ref general) => match &general.log_level {
-                Some(level) => tracing::Level::from_str(&level)?,
-                None => default_log_level,
-            },
-            None => default_log_level,
+/// Resolve a (possibly relative) `SyncSettings.path` to a canonical, absolute directory.
+fn resolve_source_root(
+    path: &Path,
+    general_settings: &GeneralSettings,
+    variables: &Environment,
+) -> Result<PathBuf> {
+    let source = if path.is_relative() {
+        let base = general_settings.relative_to.eval(variables)?;
+        let absolute = base.join(path);
+        debug!(
+            "Relative dir {source} -> {absolute}",
+            source = path.display(),
+            absolute = absolute.display()
+        );
+        absolute
+    } else {
+        path.to_path_buf()
+    };
+    Ok(source.canonicalize()?)
+}
+
+/// Walk a single `SyncSettings` entry and rsync every matching file to `destination`.
+/// If `only` is given, entries not contained in it are skipped.
+async fn sync_source(
+    rsync: &Path,
+    sync: &SyncSettings,
+    general_settings: &GeneralSettings,
+    variables: &Environment,
+    destination: &str,
+    ssh_command: Option<&str>,
+    link_dest: Option<&str>,
+    dry_run: bool,
+    rsync_dry_run: bool,
+    only: Option<&HashSet<PathBuf>>,
+) -> Result<()> {
+    let source = resolve_source_root(&sync.path, general_settings, variables)?;
+
+    let mut excludes = HashSet::new();
+    for e in &sync.exclude {
+        excludes.insert(e);
+    }
+    for e in &general_settings.exclude {
+        excludes.insert(e);
+    }
+    let mut glob_builder = GlobSetBuilder::new();
+    for e in excludes {
+        glob_builder.add(Glob::new(e)?);
+    }
+    let glob = glob_builder.build()?;
+    let exclude_filter = move |entry: &DirEntry| {
+        let path = entry.path().as_os_str().to_str().unwrap();
+        !glob.is_match(path)
+    };
+
+    let hidden = match sync.ignore_settings.hidden {
+        Some(b) => b,
+        None => match general_settings.ignore_settings.hidden {
+            Some(b) => b,
+            None => true,
         },
     };
 
-    let subscriber = FmtSubscriber::builder().with_max_level(log_level).finish();
-    tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
+    let parents = match sync.ignore_settings.parents {
+        Some(b) => b,
+        None => match general_settings.ignore_settings.parents {
+            Some(b) => b,
+            None => true,
+        },
+    };
 
-    debug!("config: {:?}", &config);
-    debug!("args: {:?}", args);
+    let ignore = match sync.ignore_settings.ignore {
+        Some(b) => b,
+        None => match general_settings.ignore_settings.ignore {
+            Some(b) => b,
+            None => true,
+        },
+    };
+
+    let git_ignore = match sync.ignore_settings.git_ignore {
+        Some(b) => b,
+        None => match general_settings.ignore_settings.git_ignore {
+            Some(b) => b,
+            None => true,
+        },
+    };
+
+    let git_global = match sync.ignore_settings.git_global {
+        Some(b) => b,
+        None => match general_settings.ignore_settings.git_global {
+            Some(b) => b,
+            None => true,
+        },
+    };
+
+    let git_exclude = match sync.ignore_settings.git_exclude {
+        Some(b) => b,
+        None => match general_settings.ignore_settings.git_exclude {
+            Some(b) => b,
+            None => true,
+        },
+    };
+
+    let same_file_system = match sync.ignore_settings.same_file_system {
+        Some(b) => b,
+        None => match general_settings.ignore_settings.same_file_system {
+            Some(b) => b,
+            None => true,
+        },
+    };
+
+    let walker = WalkBuilder::new(&source)
+        .hidden(hidden)
+        .parents(parents)
+        .ignore(ignore)
+        .git_ignore(git_ignore)
+        .git_global(git_global)
+        .git_exclude(git_exclude)
+        .same_file_system(same_file_system)
+        .filter_entry(exclude_filter)
+        .build();
+    let mut file_list = NamedTempFile::new()?;
+    let mut entry_count = 0usize;
+    for result in walker {
+        let result = result?;
+        let path = result.path();
+
+        if let Some(only) = only {
+            if !only.contains(path) {
+                continue;
+            }
+        }
+
+        let relative = path.strip_prefix(&source)?;
+        if relative.as_os_str().is_empty() {
+            // The source root itself; rsync transfers it implicitly as the base.
+            continue;
+        }
+        writeln!(file_list, "{}", relative.display())?;
+        entry_count += 1;
+    }
+
+    if entry_count == 0 {
+        debug!("Nothing to sync for {}", source.display());
+        return Ok(());
+    }
+    file_list.flush()?;
+
+    let mut cmd = Command::new(rsync);
+    let files_from = format!("--files-from={}", file_list.path().display());
+    let source_arg = format!("{}/", source.display());
+    let link_dest_arg = link_dest.map(|ld| format!("--link-dest={}", ld));
+    let mut args = vec!["--archive", "--verbose", "--compress", &files_from, "--relative"];
+    if let Some(ssh_command) = ssh_command {
+        args.push("-e");
+        args.push(ssh_command);
+    }
+    if let Some(link_dest_arg) = &link_dest_arg {
+        args.push(link_dest_arg);
+    }
+    if rsync_dry_run {
+        args.push("--dry-run");
+    }
+    args.push(&source_arg);
+    args.push(destination);
+    cmd.args(args);
+
+    info!(
+        "Syncing {} entries from {} to {}",
+        entry_count,
+        source.display(),
+        destination
+    );
+    if dry_run {
+        println!("[dry-run] {:?}", cmd);
+    } else {
+        // Create a oneshot to get back the status code of the child process once it finishes to our main task
+        let (tx, rx) = oneshot::channel();
+
+        cmd.stdout(Stdio::piped());
+
+        let mut child = cmd.spawn().expect("Failed to start child process (rsync)");
+
+        let stdout = child
+            .stdout
+            .take()
+            .expect("Unable to take handle to child's (rsync) stdout");
+
+        let mut reader = BufReader::new(stdout).lines();
+        tokio::spawn(async move {
+            let status = child
+                .wait()
+                .await
+                .expect("Child process (rsync) encountered an error");
+            // Send the status to the main task
+            tx.send(status).unwrap(); // TODO: handle error somehow
+        });
+        while let Some(line) = reader.next_line().await? {
+            println!("[rsync] {}", line);
+        }
+        let Ok(status) = rx.await else {
+            bail!("Unable to get status code from child process (rsync)");
+        };
+        let Some(code) = status.code() else {
+            bail!("Unable to get status code from child process (rsync)");
+        };
+        println!("rsync exited with code {}", code);
+    }
+
+    Ok(())
+}
+
+/// Watch every configured source root and re-run `sync_source` whenever files under it
+/// change, coalescing bursts of events behind `general_settings.debounce_ms`.
+async fn watch_and_sync(
+    rsync: &Path,
+    config: &Config,
+    general_settings: &GeneralSettings,
+    variables: &Environment,
+    destination: &str,
+    ssh_command: Option<&str>,
+    link_dest: Option<&str>,
+    dry_run: bool,
+    rsync_dry_run: bool,
+) -> Result<()> {
+    let (tx, mut rx) = mpsc::unbounded_channel::<Event>();
+    let mut watcher = RecommendedWatcher::new(
+        move |res: notify::Result<Event>| match res {
+            Ok(event) => {
+                let _ = tx.send(event);
+            }
+            Err(err) => warn!("Watch error: {}", err),
+        },
+        notify::Config::default(),
+    )?;
+
+    for sync in &config.sync {
+        let root = resolve_source_root(&sync.path, general_settings, variables)?;
+        info!("Watching {}", root.display());
+        watcher.watch(&root, RecursiveMode::Recursive)?;
+    }
+
+    let debounce = Duration::from_millis(general_settings.debounce_ms);
+    info!("Entering watch mode (debounce: {:?})", debounce);
+
+    while let Some(first) = rx.recv().await {
+        let mut changed: HashSet<PathBuf> = HashSet::new();
+        changed.extend(first.paths);
+
+        let deadline = tokio::time::sleep(debounce);
+        tokio::pin!(deadline);
+        loop {
+            tokio::select! {
+                _ = &mut deadline => break,
+                event = rx.recv() => match event {
+                    Some(event) => {
+                        changed.extend(event.paths);
+                        deadline.as_mut().reset(tokio::time::Instant::now() + debounce);
+                    }
+                    None => break,
+                },
+            }
+        }
+
+        debug!("Re-syncing {} changed path(s)", changed.len());
+        for sync in &config.sync {
+            sync_source(
+                rsync,
+                sync,
+                general_settings,
+                variables,
+                destination,
+                ssh_command,
+                link_dest,
+                dry_run,
+                rsync_dry_run,
+                Some(&changed),
+            )
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Ping a remote host to confirm it's reachable before trusting it with a backup.
+async fn ping_host(host: IpAddr) -> bool {
+    Command::new("ping")
+        .args(["-c", "1", "-W", "2", &host.to_string()])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .await
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
+
+/// Validate `config.toml` and the surrounding environment, reporting every problem found.
+async fn check(config: &Config, general_settings: &GeneralSettings) -> Result<()> {
+    let mut problems = Vec::new();
+
+    match which("rsync") {
+        Ok(path) => debug!("rsync: {}", path.display()),
+        Err(err) => problems.push(format!("rsync is not on PATH: {}", err)),
+    }
+    match which("ssh") {
+        Ok(path) => debug!("ssh: {}", path.display()),
+        Err(err) => problems.push(format!("ssh is not on PATH: {}", err)),
+    }
+
+    let local_hostname = gethostname::gethostname();
+    let local_hostname = local_hostname.to_str().unwrap_or_default();
+    let variables = EnvironmentBuilder::default()
+        .with_process_env()
+        .set("timestamp", "0")
+        .set("hostname", local_hostname)
+        .build();
+
+    for sync in &config.sync {
+        match resolve_source_root(&sync.path, general_settings, &variables) {
+            Ok(source) => match fs::metadata(&source) {
+                Ok(meta) => {
+                    let readable = if meta.is_dir() {
+                        fs::read_dir(&source).is_ok()
+                    } else {
+                        fs::File::open(&source).is_ok()
+                    };
+                    if !readable {
+                        problems.push(format!("Source {} is not readable", source.display()));
+                    }
+                }
+                Err(err) => problems.push(format!(
+                    "Source {} does not exist: {}",
+                    source.display(),
+                    err
+                )),
+            },
+            Err(err) => problems.push(format!(
+                "Unable to resolve source path '{}': {}",
+                sync.path.display(),
+                err
+            )),
+        }
+
+        for pattern in sync.exclude.iter().chain(general_settings.exclude.iter()) {
+            if let Err(err) = Glob::new(pattern) {
+                problems.push(format!("Invalid exclude glob '{}': {}", pattern, err));
+            }
+        }
+    }
+
+    if config.remotes.is_empty() {
+        problems.push(String::from("No remotes configured"));
+    }
+    for remote in &config.remotes {
+        if let Some(ssh) = &remote.ssh {
+            if let Some(identity_file) = &ssh.identity_file {
+                match identity_file.eval(&variables) {
+                    Ok(path) if !path.exists() => problems.push(format!(
+                        "Remote '{}' identity file {} does not exist",
+                        remote.name,
+                        path.display()
+                    )),
+                    Err(err) => problems.push(format!(
+                        "Remote '{}' has an invalid identity_file: {}",
+                        remote.name, err
+                    )),
+                    Ok(_) => {}
+                }
+            }
+        }
+
+        if !ping_host(remote.host).await {
+            problems.push(format!(
+                "Remote '{}' ({}) did not respond to ping",
+                remote.name, remote.host
+            ));
+        }
+    }
+
+    if problems.is_empty() {
+        println!("OK: configuration looks good");
+        return Ok(());
+    }
+
+    for problem in &problems {
+        println!("- {}", problem);
+    }
+    bail!("{} problem(s) found", problems.len());
+}
+
+async fn run(run_args: &RunArgs, config: &Config, general_settings: &GeneralSettings) -> Result<()> {
+    let dry_run = run_args.dry_run;
+    let rsync_dry_run = run_args.rsync_dry_run;
 
     let rsync = which("rsync")?;
     debug!("rsync: {}", rsync.display());
@@ -201,14 +946,6 @@ async fn main() -> Result<()> {
     let local_hostname = gethostname::gethostname();
     let local_hostname = local_hostname.to_str().unwrap();
 
-    let user = &remote_settings.user;
-    let remote_host = remote_settings.host;
-    let remote = format!(
-        "{user}@{remote_host}",
-        user = user,
-        remote_host = remote_host
-    );
-
     let timestamp = Timestamp::now().intz(&general_settings.timezone)?;
     let formatted_timestamp = timestamp
         .strftime(&general_settings.timestamp_fmt)
@@ -220,6 +957,24 @@ async fn main() -> Result<()> {
         .set("timestamp", &formatted_timestamp)
         .set("hostname", local_hostname)
         .build();
+
+    let remote_settings = select_remote(
+        &rsync,
+        &config.remotes,
+        &variables,
+        run_args.remote.as_deref(),
+        run_args.no_probe,
+    )
+    .await?;
+
+    let user = &remote_settings.user;
+    let remote_host = remote_settings.host;
+    let remote = format!(
+        "{user}@{remote_host}",
+        user = user,
+        remote_host = remote_host
+    );
+
     let destination_dir = &remote_settings.destination.eval(&variables)?;
 
     let destination = format!(
@@ -228,156 +983,191 @@ async fn main() -> Result<()> {
         dest_dir = destination_dir.display(),
     );
 
-    for sync in &config.sync {
-        let source = &sync.path;
-        let source = if source.is_relative() {
-            let base = general_settings.relative_to.eval(&variables)?;
-            let absolute = base.join(source);
-            debug!(
-                "Relative dir {source} -> {absolute}",
-                source = source.display(),
-                absolute = absolute.display()
-            );
-            absolute
-        } else {
-            source.to_path_buf()
-        };
-        let source = source.canonicalize()?;
+    let ssh_command = match &remote_settings.ssh {
+        Some(ssh) => Some(build_ssh_command(ssh, &variables)?),
+        None => None,
+    };
 
-        let mut excludes = HashSet::new();
-        for e in &sync.exclude {
-            excludes.insert(e);
-        }
-        for e in &general_settings.exclude {
-            excludes.insert(e);
+    let snapshot_mode = is_snapshot_destination(remote_settings);
+    let mut link_dest: Option<String> = None;
+    if snapshot_mode {
+        if let Some(parent) = destination_dir.parent() {
+            match list_remote_snapshots(
+                remote_settings,
+                &variables,
+                parent,
+                &general_settings.timestamp_fmt,
+            )
+            .await
+            {
+                Ok(snapshots) => {
+                    if let Some((_, previous)) = snapshots.last() {
+                        link_dest = Some(parent.join(previous).display().to_string());
+                    }
+                }
+                Err(err) => warn!("Unable to determine previous snapshot for --link-dest: {}", err),
+            }
         }
-        let mut glob_builder = GlobSetBuilder::new();
-        for e in excludes {
-            glob_builder.add(Glob::new(e)?);
+    }
+
+    for sync in &config.sync {
+        sync_source(
+            &rsync,
+            sync,
+            general_settings,
+            &variables,
+            &destination,
+            ssh_command.as_deref(),
+            link_dest.as_deref(),
+            dry_run,
+            rsync_dry_run,
+            None,
+        )
+        .await?;
+    }
+
+    if snapshot_mode {
+        if let Some(retention) = &general_settings.retention {
+            if let Some(parent) = destination_dir.parent() {
+                prune_snapshots(
+                    remote_settings,
+                    &variables,
+                    parent,
+                    &general_settings.timestamp_fmt,
+                    retention,
+                    dry_run,
+                )
+                .await?;
+            }
         }
-        let glob = glob_builder.build()?;
-        let exclude_filter = move |entry: &DirEntry| {
-            let path = entry.path().as_os_str().to_str().unwrap();
-            !glob.is_match(path)
-        };
+    }
 
-        let hidden = match sync.ignore_settings.hidden {
-            Some(b) => b,
-            None => match general_settings.ignore_settings.hidden {
-                Some(b) => b,
-                None => true,
-            },
-        };
+    if run_args.watch {
+        watch_and_sync(
+            &rsync,
+            config,
+            general_settings,
+            &variables,
+            &destination,
+            ssh_command.as_deref(),
+            link_dest.as_deref(),
+            dry_run,
+            rsync_dry_run,
+        )
+        .await?;
+    }
 
-        let parents = match sync.ignore_settings.parents {
-            Some(b) => b,
-            None => match general_settings.ignore_settings.parents {
-                Some(b) => b,
-                None => true,
-            },
-        };
+    Ok(())
+}
 
-        let ignore = match sync.ignore_settings.ignore {
-            Some(b) => b,
-            None => match general_settings.ignore_settings.ignore {
-                Some(b) => b,
-                None => true,
-            },
-        };
+#[tokio::main]
+async fn main() -> Result<()> {
+    color_eyre::install()?;
+    let default_log_level = tracing::Level::DEBUG;
 
-        let git_ignore = match sync.ignore_settings.git_ignore {
-            Some(b) => b,
-            None => match general_settings.ignore_settings.git_ignore {
-                Some(b) => b,
-                None => true,
-            },
-        };
+    let Some(proj_dirs) = ProjectDirs::from("", "", THIS_CRATE_NAME) else {
+        bail!("Unable to get XDG dirs");
+    };
+    let config_dir = proj_dirs.config_dir();
+    let config_file = config_dir.join("config.toml");
+    let contents = fs::read_to_string(&config_file)?;
+    let config: Config = toml::from_str(&contents)?;
+    let general_settings = match config.general {
+        Some(ref general) => general.clone(),
+        None => GeneralSettings::default(),
+    };
 
-        let git_global = match sync.ignore_settings.git_global {
-            Some(b) => b,
-            None => match general_settings.ignore_settings.git_global {
-                Some(b) => b,
-                None => true,
+    let args = Cli::parse();
+    let log_level = match args.log_level {
+        Some(level) => level,
+        None => match config.general {
+            Some(ref general) => match &general.log_level {
+                Some(level) => tracing::Level::from_str(&level)?,
+                None => default_log_level,
             },
-        };
+            None => default_log_level,
+        },
+    };
 
-        let git_exclude = match sync.ignore_settings.git_exclude {
-            Some(b) => b,
-            None => match general_settings.ignore_settings.git_exclude {
-                Some(b) => b,
-                None => true,
-            },
+    let subscriber = FmtSubscriber::builder().with_max_level(log_level).finish();
+    tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
+
+    debug!("config: {:?}", &config);
+    debug!("args: {:?}", args);
+
+    match args.command {
+        Commands::Run(run_args) => run(&run_args, &config, &general_settings).await,
+        Commands::Check => check(&config, &general_settings).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(ts: &str, name: &str) -> (Timestamp, String) {
+        (ts.parse().unwrap(), name.to_string())
+    }
+
+    #[test]
+    fn shell_quote_escapes_embedded_single_quotes() {
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+    }
+
+    #[test]
+    fn shell_quote_preserves_spaces_as_one_token() {
+        assert_eq!(shell_quote("a b"), "'a b'");
+    }
+
+    #[test]
+    fn build_ssh_command_quotes_options_containing_spaces() {
+        let ssh = SshSettings {
+            port: None,
+            identity_file: None,
+            known_hosts: None,
+            strict_host_key_checking: None,
+            options: vec![String::from("ProxyCommand=ssh -W %h:%p jump.example.com")],
         };
+        let variables = EnvironmentBuilder::default().build();
 
-        let same_file_system = match sync.ignore_settings.same_file_system {
-            Some(b) => b,
-            None => match general_settings.ignore_settings.same_file_system {
-                Some(b) => b,
-                None => true,
-            },
+        let command = build_ssh_command(&ssh, &variables).unwrap();
+
+        assert_eq!(
+            command,
+            "ssh '-o' 'ProxyCommand=ssh -W %h:%p jump.example.com'"
+        );
+    }
+
+    #[test]
+    fn snapshots_to_prune_keeps_everything_with_empty_retention() {
+        let snapshots = vec![
+            snapshot("2024-01-01T00:00:00Z", "2024-01-01"),
+            snapshot("2024-06-01T00:00:00Z", "2024-06-01"),
+        ];
+        let retention = RetentionSettings {
+            keep_last: None,
+            keep_newer_than_days: None,
         };
 
-        let walker = WalkBuilder::new(&source)
-            .hidden(hidden)
-            .parents(parents)
-            .ignore(ignore)
-            .git_ignore(git_ignore)
-            .git_global(git_global)
-            .git_exclude(git_exclude)
-            .same_file_system(same_file_system)
-            .filter_entry(exclude_filter)
-            .build();
-        for result in walker {
-            let mut cmd = Command::new(&rsync);
-            let result = result?;
-            let source = result.path();
-
-            let mut args = vec!["--archive", "--verbose", "--compress"];
-            if rsync_dry_run {
-                args.push("--dry-run");
-            }
-            args.push(source.to_str().unwrap());
-            args.push(&destination);
-            cmd.args(args);
-
-            info!("Syncing {} to {}", source.display(), &destination);
-            if dry_run {
-                println!("[dry-run] {:?}", cmd);
-            } else {
-                // Create a oneshot to get back the status code of the child process once it finishes to our main task
-                let (tx, rx) = oneshot::channel();
-
-                cmd.stdout(Stdio::piped());
-
-                let mut child = cmd.spawn().expect("Failed to start child process (rsync)");
-
-                let stdout = child
-                    .stdout
-                    .take()
-                    .expect("Unable to take handle to child's (rsync) stdout");
-
-                let mut reader = BufReader::new(stdout).lines();
-                tokio::spawn(async move {
-                    let status = child
-                        .wait()
-                        .await
-                        .expect("Child process (rsync) encountered an error");
-                    // Send the status to the main task
-                    tx.send(status).unwrap(); // TODO: handle error somehow
-                });
-                while let Some(line) = reader.next_line().await? {
-                    println!("[rsync] {}", line);
-                }
-                let Ok(status) = rx.await else {
-                    bail!("Unable to get status code from child process (rsync)");
-                };
-                let Some(code) = status.code() else {
-                    bail!("Unable to get status code from child process (rsync)");
-                };
-                println!("rsync exited with code {}", code);
-            }
-        }
+        let prune = snapshots_to_prune(&snapshots, &retention).unwrap();
+
+        assert!(prune.is_empty());
     }
 
-    Ok(())
+    #[test]
+    fn snapshots_to_prune_respects_keep_last() {
+        let snapshots = vec![
+            snapshot("2024-01-01T00:00:00Z", "2024-01-01"),
+            snapshot("2024-02-01T00:00:00Z", "2024-02-01"),
+            snapshot("2024-03-01T00:00:00Z", "2024-03-01"),
+        ];
+        let retention = RetentionSettings {
+            keep_last: Some(1),
+            keep_newer_than_days: None,
+        };
+
+        let prune = snapshots_to_prune(&snapshots, &retention).unwrap();
+
+        assert_eq!(prune, vec!["2024-01-01", "2024-02-01"]);
+    }
 }